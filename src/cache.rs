@@ -0,0 +1,182 @@
+use crate::config::{CacheKey, Config};
+use crate::prelude::*;
+use crate::select::input_cache_passphrase;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::Hmac;
+use once_cell::sync::OnceCell;
+use sha2::Sha256;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+// file layout: MAGIC | salt | nonce | ciphertext
+const MAGIC: &[u8] = b"ASH1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const PBKDF2_ROUNDS: u32 = 100_000;
+const KEYRING_SERVICE: &str = "ash";
+const KEYRING_USER: &str = "credential-cache";
+
+static KEYRING_KEY: OnceCell<Key> = OnceCell::new();
+static PASSPHRASE: OnceCell<String> = OnceCell::new();
+
+fn keyring_key() -> Result<Key> {
+    let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER)?;
+    match entry.get_password() {
+        Ok(hex) => decode_hex(&hex)
+            .filter(|k| k.len() == 32)
+            .map(|k| *Key::from_slice(&k))
+            .ok_or_else(|| eyre!("invalid cache key in keyring")),
+        Err(keyring::Error::NoEntry) => {
+            let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+            entry.set_password(&encode_hex(&key))?;
+            Ok(key)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Random key in a user-only file next to the cache, for hosts without a keyring service
+/// (CI runners, jump hosts); weaker than the keyring but keeps the cache working
+fn key_file_key() -> Result<Key> {
+    let path = Config::cache_key_path();
+    if let Some(key) = std::fs::read_to_string(&path).ok().and_then(|hex| decode_hex(hex.trim())) {
+        ensure!(key.len() == 32, "invalid cache key in {path:?}");
+        return Ok(*Key::from_slice(&key));
+    }
+    let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+    write_private(&path, encode_hex(&key).as_bytes())?;
+    p!("No OS keyring available, the credential cache key is kept in {path:?}");
+    Ok(key)
+}
+
+fn passphrase_key(salt: &[u8]) -> Result<Key> {
+    let passphrase =
+        PASSPHRASE.get_or_try_init(|| match std::env::var("ASH_CACHE_PASSPHRASE") {
+            Ok(passphrase) if !passphrase.is_empty() => Ok(passphrase),
+            _ => input_cache_passphrase(),
+        })?;
+    let mut key = Key::default();
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+    Ok(key)
+}
+
+fn key(source: CacheKey, salt: &[u8]) -> Result<Key> {
+    match source {
+        CacheKey::Keyring => {
+            KEYRING_KEY.get_or_try_init(|| keyring_key().or_else(|_| key_file_key())).copied()
+        }
+        CacheKey::Passphrase => passphrase_key(salt),
+    }
+}
+
+fn encrypt(key: &Key, salt: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let encrypted = ChaCha20Poly1305::new(key)
+        .encrypt(&nonce, data)
+        .map_err(|_| eyre!("can't encrypt cache"))?;
+    Ok([MAGIC, salt, &nonce, &encrypted].concat())
+}
+
+fn decrypt(key: &Key, content: &[u8]) -> Result<Vec<u8>> {
+    let (nonce, encrypted) = content[MAGIC.len() + SALT_LEN..].split_at(NONCE_LEN);
+    ChaCha20Poly1305::new(key)
+        .decrypt(Nonce::from_slice(nonce), encrypted)
+        .map_err(|_| eyre!("can't decrypt cache"))
+}
+
+fn salt(content: &[u8]) -> Option<&[u8]> {
+    let header = MAGIC.len() + SALT_LEN + NONCE_LEN;
+    (content.len() > header && content.starts_with(MAGIC))
+        .then(|| &content[MAGIC.len()..MAGIC.len() + SALT_LEN])
+}
+
+/// Reads and decrypts the cache at `path`.
+/// A corrupted or undecryptable cache is removed, so the next `write` rebuilds it.
+pub fn read(path: &Path, source: CacheKey) -> Option<Vec<u8>> {
+    let content = std::fs::read(path).ok()?;
+    let decrypted = match salt(&content) {
+        Some(salt) => match key(source, salt) {
+            Ok(key) => decrypt(&key, &content),
+            Err(err) => {
+                p!("Credential cache disabled: {err:#}");
+                return None;
+            }
+        },
+        None => Err(eyre!("unknown cache format")),
+    };
+    match decrypted {
+        Ok(data) => Some(data),
+        Err(err) => {
+            p!("Credential cache unreadable ({err:#}), rebuilding it");
+            _ = std::fs::remove_file(path);
+            None
+        }
+    }
+}
+
+/// Encrypts `data` and writes it to `path`, readable by the current user only
+pub fn write(path: &Path, source: CacheKey, data: &[u8]) -> Result<()> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let content = encrypt(&key(source, &salt)?, &salt, data)?;
+    write_private(path, &content)
+}
+
+/// Atomically replaces `path` with `content`, readable by the current user only
+fn write_private(path: &Path, content: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        if tmp.exists() {
+            std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    let mut file = options.open(&tmp).context(f!("can't write {tmp:?}"))?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path).context(f!("can't write {path:?}"))?;
+    Ok(())
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| f!("{b:02x}")).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt_roundtrip_and_tamper_detection() {
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let content = encrypt(&key, &[7; SALT_LEN], b"[]").unwrap();
+        assert_eq!(salt(&content), Some(&[7; SALT_LEN][..]));
+        assert_eq!(decrypt(&key, &content).unwrap(), b"[]");
+        let mut tampered = content.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&key, &tampered).is_err());
+        let other = ChaCha20Poly1305::generate_key(&mut OsRng);
+        assert!(decrypt(&other, &content).is_err());
+        assert!(salt(b"[{\"profile\":\"prod\"}]").is_none());
+    }
+
+    #[test]
+    fn hex_roundtrip() {
+        assert_eq!(
+            decode_hex(&encode_hex(&[0, 15, 255])).unwrap(),
+            [0, 15, 255]
+        );
+        assert!(decode_hex("zz").is_none());
+    }
+}
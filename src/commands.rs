@@ -1,6 +1,8 @@
 use crate::config::Config;
 use crate::config::Service;
 use crate::config::COMMON_SSH_ARGS;
+use crate::inventory::format_age;
+use crate::inventory::Inventory;
 use crate::parsers::ssh_config_parser::Host;
 use crate::parsers::ssh_config_parser::Platform;
 use crate::prelude::*;
@@ -85,6 +87,43 @@ pub enum Commands {
         #[command(subcommand)]
         container: Container,
     },
+    /// Update ssh config from aws
+    #[command()]
+    Update {
+        /// Show the host changes without writing anything
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+    /// Manage the ssh config written by ash
+    #[command(arg_required_else_help = true)]
+    Config {
+        #[command(subcommand)]
+        config: ConfigCommand,
+    },
+    /// Work with the ssh config template
+    #[command(arg_required_else_help = true)]
+    Template {
+        #[command(subcommand)]
+        template: TemplateCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TemplateCommand {
+    /// Render the template against the inventory and parse it back, without writing anything
+    #[command()]
+    Check,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Restore a backup of the ssh config, 1 is the latest
+    #[command()]
+    Restore {
+        /// Backup to restore
+        #[arg(default_value_t = 1)]
+        n: usize,
+    },
 }
 
 #[derive(Subcommand)]
@@ -113,19 +152,18 @@ pub enum Container {
 impl Commands {
     pub fn tunnel_from_ports(
         TunnelArgs { local, remote }: TunnelArgs,
-        hosts @ Hosts { bastion, .. }: &Hosts,
+        hosts: &Hosts,
     ) -> Result<()> {
-        if bastion.is_empty() {
-            bail!("Can't tunnel without bastion");
-        }
-        let bastion = hosts
-            .hosts
-            .get(bastion)
-            .ok_or_else(|| eyre!("Can't find bastion {bastion:?}"))?
-            .clone();
-        let bastion_name = &bastion.name;
         let choice = select_profile_then_host(hosts)?;
-        let Host { name, address, .. } = &hosts.hosts[&choice];
+        let Host { name, address, bastion, .. } = &hosts.hosts[&choice];
+        // the host's own ProxyJump, else the global bastion
+        let bastion = match bastion.as_deref() {
+            Some(bastion) if !bastion.is_empty() => bastion,
+            _ if !hosts.bastion.is_empty() => &hosts.bastion,
+            _ => bail!("Can't tunnel to {name} without bastion"),
+        };
+        let bastion_name =
+            &hosts.hosts.get(bastion).ok_or_else(|| eyre!("Can't find bastion {bastion:?}"))?.name;
         p!("Tunneling from {local} to {name}:{remote} through {bastion_name} ...");
         Command::new("ssh")
             .args(COMMON_SSH_ARGS)
@@ -203,10 +241,18 @@ impl Commands {
         Ok(())
     }
 
+    /// Prints the host from the ssh config, with what the inventory knows about it
     pub fn info(hosts: &Hosts) -> Result<()> {
         let choice = select_profile_then_host(hosts)?;
-        let host = serde_json::to_string_pretty(&hosts.hosts[&choice])?;
-        p!("{host}");
+        let host = &hosts.hosts[&choice];
+        let mut info = serde_json::to_value(host)?;
+        if let Some((snapshot, instance)) = Inventory::load().find(&host.profile, &host.name) {
+            let mut inventory = serde_json::to_value(instance)?;
+            inventory["updated"] = snapshot.updated.clone().into();
+            inventory["age"] = format_age(snapshot.age()).into();
+            info["inventory"] = inventory;
+        }
+        p!("{}", serde_json::to_string_pretty(&info)?);
         Ok(())
     }
 
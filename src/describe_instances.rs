@@ -1,53 +1,250 @@
+//! Typed EC2 `DescribeInstances` and `DescribeImages` responses, read from the query api xml
+use crate::prelude::*;
+use roxmltree::{Document, Node};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct DescribeInstances {
-    #[serde(rename = "Reservations")]
     pub reservations: Vec<Reservation>,
+    pub next_token: Option<String>,
+    /// Instances that couldn't be read, left out of `reservations`
+    pub skipped: Vec<Skipped>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Skipped {
+    pub instance_id: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Reservation {
-    #[serde(rename = "Instances")]
+    pub reservation_id: String,
+    pub owner_id: String,
     pub instances: Vec<Instance>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Instance {
-    #[serde(rename = "ImageId")]
     pub image_id: String,
-    #[serde(rename = "InstanceId")]
     pub instance_id: String,
-    #[serde(rename = "InstanceType")]
     pub instance_type: String,
-    #[serde(rename = "KeyName")]
     pub key_name: Option<String>,
-    #[serde(rename = "PrivateIpAddress")]
-    pub private_ip_address: String,
-    #[serde(rename = "PublicIpAddress")]
-    pub public_ip_address: String,
-    #[serde(rename = "State")]
+    pub private_ip_address: Option<String>,
+    pub public_ip_address: Option<String>,
+    pub ipv6_address: Option<String>,
+    pub private_dns_name: Option<String>,
+    pub public_dns_name: Option<String>,
     pub state: State,
-    #[serde(rename = "Tags")]
     pub tags: Vec<Tag>,
-    #[serde(rename = "PlatformDetails")]
-    pub platform_details: String,
-    #[serde(rename = "Platform")]
+    pub platform_details: Option<String>,
     pub platform: Option<String>,
+    pub architecture: Option<String>,
+    pub launch_time: Option<String>,
+    pub availability_zone: Option<String>,
+    pub vpc_id: Option<String>,
+    pub subnet_id: Option<String>,
+    pub security_groups: Vec<SecurityGroup>,
+    pub iam_instance_profile: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct State {
-    #[serde(rename = "Code")]
     pub code: i64,
-    #[serde(rename = "Name")]
     pub name: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Tag {
-    #[serde(rename = "Key")]
     pub key: String,
-    #[serde(rename = "Value")]
     pub value: String,
 }
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SecurityGroup {
+    pub group_id: String,
+    pub group_name: String,
+}
+
+/// AMI of an instance, from `DescribeImages`
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Image {
+    pub image_id: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+impl Instance {
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.iter().find(|t| t.key == key).map(|t| t.value.as_str())
+    }
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+fn text(node: Node, name: &str) -> Option<String> {
+    child(node, name)?.text().map(str::trim).filter(|t| !t.is_empty()).map(String::from)
+}
+
+fn required(node: Node, name: &str) -> Result<String> {
+    text(node, name).ok_or_else(|| eyre!("missing <{name}>"))
+}
+
+/// `<name><item>..</item>..</name>` children, none when the set is absent
+fn items<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Vec<Node<'a, 'input>> {
+    child(node, name)
+        .map(|set| set.children().filter(|n| n.has_tag_name("item")).collect())
+        .unwrap_or_default()
+}
+
+/// First ipv6 of the network interfaces, for responses without a top level `<ipv6Address>`
+fn first_ipv6(node: Node) -> Option<String> {
+    items(node, "networkInterfaceSet")
+        .into_iter()
+        .flat_map(|eni| items(eni, "ipv6AddressesSet"))
+        .find_map(|ip| text(ip, "ipv6Address"))
+}
+
+impl State {
+    fn from_node(node: Node) -> Result<Self> {
+        let code = required(node, "code")?.parse().context("invalid instanceState code")?;
+        Ok(Self { code, name: required(node, "name")? })
+    }
+}
+
+impl Tag {
+    fn from_node(node: Node) -> Result<Self> {
+        Ok(Self { key: required(node, "key")?, value: text(node, "value").unwrap_or_default() })
+    }
+}
+
+impl SecurityGroup {
+    fn from_node(node: Node) -> Result<Self> {
+        Ok(Self {
+            group_id: required(node, "groupId")?,
+            group_name: text(node, "groupName").unwrap_or_default(),
+        })
+    }
+}
+
+impl Instance {
+    fn from_node(node: Node) -> Result<Self> {
+        let state = child(node, "instanceState").ok_or_else(|| eyre!("missing <instanceState>"))?;
+        Ok(Self {
+            image_id: required(node, "imageId")?,
+            instance_id: required(node, "instanceId")?,
+            instance_type: required(node, "instanceType")?,
+            key_name: text(node, "keyName"),
+            private_ip_address: text(node, "privateIpAddress"),
+            public_ip_address: text(node, "ipAddress"),
+            ipv6_address: text(node, "ipv6Address").or_else(|| first_ipv6(node)),
+            private_dns_name: text(node, "privateDnsName"),
+            public_dns_name: text(node, "dnsName"),
+            state: State::from_node(state)?,
+            tags: items(node, "tagSet").into_iter().map(Tag::from_node).collect::<Result<_>>()?,
+            platform_details: text(node, "platformDetails"),
+            platform: text(node, "platform"),
+            architecture: text(node, "architecture"),
+            launch_time: text(node, "launchTime"),
+            availability_zone: child(node, "placement").and_then(|p| text(p, "availabilityZone")),
+            vpc_id: text(node, "vpcId"),
+            subnet_id: text(node, "subnetId"),
+            security_groups: items(node, "groupSet")
+                .into_iter()
+                .map(SecurityGroup::from_node)
+                .collect::<Result<_>>()?,
+            iam_instance_profile: child(node, "iamInstanceProfile").and_then(|p| text(p, "arn")),
+        })
+    }
+}
+
+impl Image {
+    fn from_node(node: Node) -> Result<Self> {
+        Ok(Self {
+            image_id: required(node, "imageId")?,
+            name: text(node, "name"),
+            description: text(node, "description"),
+        })
+    }
+
+    /// Images of a `DescribeImages` response; malformed entries are left out
+    pub fn parse(xml: &str) -> Result<Vec<Self>> {
+        let doc = Document::parse(xml)?;
+        let root = doc.root_element();
+        ensure!(
+            root.has_tag_name("DescribeImagesResponse"),
+            "unexpected DescribeImages response <{}>",
+            root.tag_name().name()
+        );
+        Ok(items(root, "imagesSet").into_iter().filter_map(|i| Self::from_node(i).ok()).collect())
+    }
+}
+
+impl Reservation {
+    /// Reads the reservation; instances that can't be read go to `skipped` instead
+    fn from_node(node: Node, skipped: &mut Vec<Skipped>) -> Self {
+        let mut instances = vec![];
+        for instance in items(node, "instancesSet") {
+            match Instance::from_node(instance) {
+                Ok(i) => instances.push(i),
+                Err(err) => skipped.push(Skipped {
+                    instance_id: text(instance, "instanceId").unwrap_or_else(|| "?".into()),
+                    reason: f!("{err:#}"),
+                }),
+            }
+        }
+        Self {
+            reservation_id: text(node, "reservationId").unwrap_or_default(),
+            owner_id: text(node, "ownerId").unwrap_or_default(),
+            instances,
+        }
+    }
+}
+
+impl DescribeInstances {
+    pub fn parse(xml: &str) -> Result<Self> {
+        let doc = Document::parse(xml)?;
+        let root = doc.root_element();
+        ensure!(
+            root.has_tag_name("DescribeInstancesResponse"),
+            "unexpected DescribeInstances response <{}>",
+            root.tag_name().name()
+        );
+        let mut skipped = vec![];
+        let reservations = items(root, "reservationSet")
+            .into_iter()
+            .map(|r| Reservation::from_node(r, &mut skipped))
+            .collect();
+        Ok(Self { reservations, next_token: text(root, "nextToken"), skipped })
+    }
+
+    pub fn instances(&self) -> impl Iterator<Item = &Instance> {
+        self.reservations.iter().flat_map(|r| &r.instances)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_skips_malformed_instances() {
+        let xml = r#"<DescribeInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+  <reservationSet><item><reservationId>r-1</reservationId><instancesSet>
+    <item><instanceId>i-good</instanceId><imageId>ami-1</imageId><instanceType>t3.micro</instanceType>
+      <instanceState><code>16</code><name>running</name></instanceState>
+      <tagSet><item><key>Name</key><value>web</value></item></tagSet></item>
+    <item><instanceId>i-bad</instanceId><imageId>ami-1</imageId><instanceType>t3.micro</instanceType>
+      <instanceState><code>16</code><name>running</name></instanceState>
+      <tagSet><item><value>no key</value></item></tagSet></item>
+  </instancesSet></item></reservationSet>
+</DescribeInstancesResponse>"#;
+        let page = DescribeInstances::parse(xml).unwrap();
+        let ids = page.instances().map(|i| i.instance_id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, ["i-good"]);
+        assert_eq!(page.skipped[0].instance_id, "i-bad");
+        assert_eq!(page.skipped[0].reason, "missing <key>");
+    }
+}
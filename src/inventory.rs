@@ -0,0 +1,150 @@
+//! Last discovery result per profile, so hosts can be inspected without calling aws
+use crate::aws::{dedup_host_names, Instance};
+use crate::config::Config;
+use crate::prelude::*;
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Inventory {
+    pub profiles: BTreeMap<String, ProfileSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProfileSnapshot {
+    /// rfc3339 time of the discovery
+    pub updated: String,
+    pub hosts: Vec<Instance>,
+}
+
+impl ProfileSnapshot {
+    pub fn new(hosts: Vec<Instance>) -> Self {
+        Self { updated: Utc::now().to_rfc3339(), hosts }
+    }
+
+    /// Time since the discovery, as old as possible when `updated` can't be read
+    pub fn age(&self) -> Duration {
+        DateTime::parse_from_rfc3339(&self.updated)
+            .ok()
+            .and_then(|updated| (Utc::now() - updated.with_timezone(&Utc)).to_std().ok())
+            .unwrap_or(Duration::MAX)
+    }
+}
+
+impl Inventory {
+    pub fn load() -> Self {
+        let path = Config::inventory_path();
+        let Ok(content) = std::fs::read(&path) else {
+            return Self::default();
+        };
+        serde_json::from_slice(&content).unwrap_or_else(|err| {
+            p!("Inventory {path:?} unreadable ({err}), it will be rebuilt on update");
+            Self::default()
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Config::inventory_path();
+        std::fs::write(&path, serde_json::to_string(self)?).context(f!("can't write {path:?}"))
+    }
+
+    /// No snapshot for `profile`, or one older than `ttl`
+    pub fn is_stale(&self, profile: &str, ttl: Duration) -> bool {
+        !matches!(self.profiles.get(profile), Some(s) if s.age() <= ttl)
+    }
+
+    /// Profiles older than `ttl` with their age
+    pub fn stale(&self, ttl: Duration) -> Vec<(&str, Duration)> {
+        self.profiles
+            .iter()
+            .filter(|(_, s)| s.age() > ttl)
+            .map(|(profile, s)| (profile.as_str(), s.age()))
+            .collect()
+    }
+
+    /// Age of every profile, e.g. `Inventory: dev 2d, prod 3h`, plus the stale ones when
+    /// there is a `ttl`; none when the inventory is empty
+    pub fn report(&self, ttl: Option<Duration>) -> Option<String> {
+        if self.profiles.is_empty() {
+            return None;
+        }
+        let ages = self.profiles.iter().map(|(p, s)| f!("{p} {}", format_age(s.age()))).join(", ");
+        let stale = ttl.map(|ttl| self.stale(ttl)).unwrap_or_default();
+        Some(match stale.is_empty() {
+            true => f!("Inventory: {ages}"),
+            false => {
+                let stale = stale.iter().map(|(p, _)| *p).join(", ");
+                f!("Inventory: {ages}; stale: {stale}, run with --update-stale to refresh")
+            }
+        })
+    }
+
+    /// Host named `name` in the ssh config, i.e. after the duplicate names got their suffix
+    pub fn find(&self, profile: &str, name: &str) -> Option<(&ProfileSnapshot, Instance)> {
+        let snapshot = self.profiles.get(profile)?;
+        let mut hosts = self.profiles.values().flat_map(|s| s.hosts.clone()).collect_vec();
+        dedup_host_names(&mut hosts);
+        Some((
+            snapshot,
+            hosts.into_iter().find(|h| h.profile == profile && h.name == name)?,
+        ))
+    }
+}
+
+/// Coarse human age, e.g. `45s`, `12m`, `3h`, `2d`
+pub fn format_age(age: Duration) -> String {
+    match age.as_secs() {
+        _ if age == Duration::MAX => "unknown".into(),
+        s if s < 60 => f!("{s}s"),
+        s if s < 3600 => f!("{}m", s / 60),
+        s if s < 86400 => f!("{}h", s / 3600),
+        s => f!("{}d", s / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_age_picks_the_largest_unit() {
+        assert_eq!(format_age(Duration::from_secs(45)), "45s");
+        assert_eq!(format_age(Duration::from_secs(12 * 60 + 5)), "12m");
+        assert_eq!(format_age(Duration::from_secs(3 * 3600)), "3h");
+        assert_eq!(format_age(Duration::from_secs(2 * 86400 + 10)), "2d");
+        let snapshot = ProfileSnapshot { updated: "garbage".into(), hosts: vec![] };
+        assert_eq!(format_age(snapshot.age()), "unknown");
+        let inventory = Inventory { profiles: BTreeMap::from([("prod".into(), snapshot)]) };
+        assert!(inventory.is_stale("prod", Duration::from_secs(60)));
+        assert!(inventory.is_stale("dev", Duration::from_secs(60)));
+        assert_eq!(
+            inventory.report(Some(Duration::from_secs(60))).unwrap(),
+            "Inventory: prod unknown; stale: prod, run with --update-stale to refresh"
+        );
+        assert_eq!(inventory.report(None).unwrap(), "Inventory: prod unknown");
+        assert!(Inventory::default().report(None).is_none());
+    }
+
+    #[test]
+    fn find_uses_the_ssh_config_names() {
+        let host = |id: &str, launch_time: &str| Instance {
+            name: "web".into(),
+            profile: "prod".into(),
+            instance_id: id.into(),
+            launch_time: launch_time.into(),
+            ..Instance::default()
+        };
+        let hosts = vec![host("i-2", "2023-02-01"), host("i-1", "2023-01-01")];
+        let inventory =
+            Inventory { profiles: BTreeMap::from([("prod".into(), ProfileSnapshot::new(hosts))]) };
+        assert_eq!(
+            inventory.find("prod", "web-2").unwrap().1.instance_id,
+            "i-2"
+        );
+        assert_eq!(inventory.profiles["prod"].hosts[0].name, "web");
+        assert!(inventory.find("dev", "web").is_none());
+    }
+}
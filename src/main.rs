@@ -3,18 +3,23 @@ use crate::select::History;
 use aws::update_sshconfig;
 use commands::*;
 use config::{Config, CFG};
+use inventory::Inventory;
 use parsers::ssh_config_parser::parse_ssh_config_from_host;
 use prelude::*;
 use std::process::exit;
 
 mod aws;
+mod cache;
 mod commands;
 mod config;
 mod describe_instances;
+mod inventory;
 mod parsers;
 mod prelude;
 mod select;
 mod ssh;
+mod ssh_config;
+mod template;
 
 fn run() -> Result<()> {
     let (config, args) = &*CFG;
@@ -26,40 +31,57 @@ fn run() -> Result<()> {
         }
         exit(0);
     }
-    if config.update {
-        update_sshconfig(
-            &config.keys_path,
-            Config::template_path(),
-            &config.bastion_name,
-        )?;
-    }
-    let hosts = parse_ssh_config_from_host()?;
-    History::intersect(&hosts);
-    let hosts = &Hosts {
-        hosts,
-        start_value: args.host.clone().unwrap_or_default(),
-        bastion: config.bastion_name.clone(),
+    let ttl = (args.update_stale && !config.update).then(|| config.inventory_ttl());
+    // hosts for the commands that pick one, refreshed first when asked to
+    let hosts = || -> Result<Hosts> {
+        if config.update || args.update_stale {
+            update_sshconfig(config, Config::template_path(), ttl, false)?;
+        } else {
+            let ttl = (config.inventory_ttl_mins > 0).then(|| config.inventory_ttl());
+            if let Some(report) = Inventory::load().report(ttl) {
+                p!("{report}");
+            }
+        }
+        let hosts = parse_ssh_config_from_host()?;
+        History::intersect(&hosts);
+        Ok(Hosts {
+            hosts,
+            start_value: args.host.clone().unwrap_or_default(),
+            bastion: config.bastion_name.clone(),
+        })
     };
     match &args.command {
         Some(cmd) => match cmd {
-            Commands::Cp(cp) => Commands::cp(cp, hosts),
-            Commands::Service { service } => Commands::tunnel_from_service(service, hosts),
-            Commands::Tunnel(tunnel) => Commands::tunnel_from_ports(*tunnel, hosts),
-            Commands::Exec { command } => Commands::exec(command, hosts),
-            Commands::Code => Commands::code(hosts),
-            Commands::Info => Commands::info(hosts),
-            Commands::EventLog => Commands::win_event_log(hosts),
-            Commands::Get => Commands::get_file(hosts),
-            Commands::Put => Commands::put_file(hosts),
-            Commands::Container { container } => match container {
-                Container::EventLog => Container::win_container_event_log(hosts),
-                Container::Vsdbg => Container::vsdbg(hosts),
-                Container::Get => Container::get_file(hosts),
-                Container::Put => Container::put_file(hosts),
-                Container::Exec { command } => Container::exec(command, hosts),
-            },
+            Commands::Cp(cp) => Commands::cp(cp, &hosts()?),
+            Commands::Service { service } => Commands::tunnel_from_service(service, &hosts()?),
+            Commands::Tunnel(tunnel) => Commands::tunnel_from_ports(*tunnel, &hosts()?),
+            Commands::Exec { command } => Commands::exec(command, &hosts()?),
+            Commands::Code => Commands::code(&hosts()?),
+            Commands::Info => Commands::info(&hosts()?),
+            Commands::EventLog => Commands::win_event_log(&hosts()?),
+            Commands::Get => Commands::get_file(&hosts()?),
+            Commands::Put => Commands::put_file(&hosts()?),
+            Commands::Update { dry_run } => {
+                update_sshconfig(config, Config::template_path(), ttl, *dry_run)
+            }
+            Commands::Config { config: ConfigCommand::Restore { n } } => {
+                ssh_config::restore(*n, config.backups)
+            }
+            Commands::Template { template: TemplateCommand::Check } => {
+                template::check(&Config::template_path(), &config.bastion_name)
+            }
+            Commands::Container { container } => {
+                let hosts = &hosts()?;
+                match container {
+                    Container::EventLog => Container::win_container_event_log(hosts),
+                    Container::Vsdbg => Container::vsdbg(hosts),
+                    Container::Get => Container::get_file(hosts),
+                    Container::Put => Container::put_file(hosts),
+                    Container::Exec { command } => Container::exec(command, hosts),
+                }
+            }
         },
-        None => Commands::ssh(hosts),
+        None => Commands::ssh(&hosts()?),
     }
 }
 
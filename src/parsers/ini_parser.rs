@@ -22,7 +22,7 @@ pub fn parse_ini(content: &str) -> Result<HashMap<String, HashMap<String, String
             Rule::property => {
                 let rules = &mut line.into_inner();
                 let name = rules.next().unwrap().as_str();
-                let value = rules.next().unwrap().as_str().to_string();
+                let value = rules.next().unwrap().as_str().trim_end().to_string();
                 profiles.get_mut(&current_section).unwrap().insert(name.to_lowercase(), value);
             }
             _ => (),
@@ -56,6 +56,17 @@ mod tests {
       
 "#;
 
+    #[test]
+    fn parse_ini_keeps_spaces_in_values() {
+        const PROCESS: &str =
+            "[profile dev]\ncredential_process = /opt/bin/broker --account=dev  \n";
+        let res = super::parse_ini(PROCESS).unwrap();
+        assert_eq!(
+            res["profile dev"]["credential_process"],
+            "/opt/bin/broker --account=dev"
+        );
+    }
+
     #[test]
     fn parse_ini_succeeds() {
         let res = super::parse_ini(INI);
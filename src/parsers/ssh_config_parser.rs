@@ -1,8 +1,9 @@
-use crate::{config::Config, prelude::*};
+use crate::{config::Config, prelude::*, ssh_config::ssh_config_path};
 use pest::Parser;
 use pest_derive::Parser;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub enum Platform {
@@ -19,6 +20,11 @@ pub struct Host {
     pub user: Option<String>,
     pub key: Option<String>,
     pub bastion: Option<String>,
+    #[serde(default)]
+    pub region: Option<String>,
+    /// File the host is defined in, none when parsed from a string
+    #[serde(default)]
+    pub source: Option<PathBuf>,
 }
 
 impl Host {
@@ -33,66 +39,185 @@ pub struct SshConfigParser;
 
 // https://www.ssh.com/academy/ssh/config
 pub fn parse_ssh_config(content: &str) -> Result<HashMap<String, Host>> {
-    // stopwatch!();
-    let res = SshConfigParser::parse(Rule::file, content)?.next().unwrap();
-    let mut hosts: HashMap<&str, HashMap<String, &str>> = HashMap::new();
-    let mut current_host = "";
-    for line in res.into_inner() {
-        match line.as_rule() {
-            Rule::host => {
-                current_host = line.into_inner().next().unwrap().as_str();
+    let mut collector = Collector::default();
+    collector.read(content, None, None)?;
+    Ok(collector.into_hosts())
+}
+
+/// Options per host in file order, the first value winning like ssh
+#[derive(Default)]
+struct Collector {
+    hosts: HashMap<String, HashMap<String, String>>,
+    sources: HashMap<String, PathBuf>,
+    /// Files being read, to catch include cycles; `None` when includes aren't followed
+    chain: Option<Vec<PathBuf>>,
+}
+
+/// ssh `Host` pattern match, `*` and `?` wildcards
+fn host_matches(pattern: &str, name: &str) -> bool {
+    match glob::Pattern::new(pattern) {
+        Ok(p) => p.matches(name),
+        Err(_) => pattern == name,
+    }
+}
+
+impl Collector {
+    /// Reads `content`, from `source` when it comes from a file. `scope` is the `Host` pattern
+    /// an `Include` sits under: only hosts it matches are read, and options before the first
+    /// `Host` line belong to it
+    fn read(&mut self, content: &str, source: Option<&Path>, scope: Option<&str>) -> Result<()> {
+        let res = SshConfigParser::parse(Rule::file, content)?.next().unwrap();
+        let mut current = scope.unwrap_or_default().to_string();
+        let mut active = true;
+        for line in res.into_inner() {
+            match line.as_rule() {
+                Rule::host => {
+                    current = line.into_inner().next().unwrap().as_str().to_string();
+                    active = !matches!(scope, Some(scope) if !host_matches(scope, &current));
+                    if let (true, Some(source)) = (active, source) {
+                        self.sources.entry(current.clone()).or_insert_with(|| source.into());
+                    }
+                }
+                Rule::profile if active => {
+                    let description = line.into_inner().next().unwrap().as_str();
+                    let (profile, platform) = description.split_once(',').ok_or_else(|| {
+                        eyre!("can't get profile and platform from '{description}'")
+                    })?;
+                    let (platform, region) = platform.split_once(',').unwrap_or((platform, ""));
+                    let host = self.hosts.entry(current.clone()).or_default();
+                    host.entry("profile".to_string()).or_insert(profile.trim().into());
+                    host.entry("platform".to_string()).or_insert(platform.trim().into());
+                    if !region.trim().is_empty() {
+                        host.entry("region".to_string()).or_insert(region.trim().into());
+                    }
+                }
+                Rule::option => {
+                    let rules = &mut line.into_inner();
+                    let keyword = rules.next().unwrap().as_str().to_lowercase();
+                    let arguments = rules.map(|r| r.as_str().trim_matches('"'));
+                    if keyword == "match" {
+                        // `Match all` is global again, other conditions are beyond the selector
+                        let all = arguments.collect::<Vec<_>>() == ["all"];
+                        current.clear();
+                        active = all && scope.is_none();
+                    } else if active && keyword == "include" {
+                        let scope = (!current.is_empty()).then_some(current.as_str());
+                        for pattern in arguments.collect::<Vec<_>>() {
+                            self.include(pattern, scope);
+                        }
+                    } else if active {
+                        let argument = arguments.collect::<Vec<_>>().join(" ");
+                        let host = self.hosts.entry(current.clone()).or_default();
+                        host.entry(keyword).or_insert(argument);
+                    }
+                }
+                _ => (),
             }
-            Rule::profile => {
-                let description = line.into_inner().next().unwrap().as_str();
-                let (profile, platform) = description
-                    .split_once(',')
-                    .ok_or_else(|| eyre!("can't get profile and platform from '{description}'"))?;
-                hosts
-                    .entry(current_host)
-                    .or_default()
-                    .insert("profile".to_string(), profile.trim());
-                hosts
-                    .entry(current_host)
-                    .or_default()
-                    .insert("platform".to_string(), platform.trim());
+        }
+        Ok(())
+    }
+
+    /// Reads the files `pattern` matches in place. Files that can't be read or parsed, or that
+    /// would include themselves, are skipped with a warning so one bad file doesn't hide the rest
+    fn include(&mut self, pattern: &str, scope: Option<&str>) {
+        if self.chain.is_none() {
+            return;
+        }
+        let paths = match include_paths(pattern) {
+            Ok(paths) => paths,
+            Err(err) => return p!("Skipping Include {pattern}: {err:#}"),
+        };
+        for path in paths {
+            let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+            let chain = self.chain.get_or_insert_with(Vec::new);
+            if chain.contains(&canonical) {
+                let cycle = chain.iter().chain([&canonical]).map(|p| p.display().to_string());
+                p!(
+                    "Skipping Include cycle: {}",
+                    cycle.collect::<Vec<_>>().join(" -> ")
+                );
+                continue;
+            }
+            let content = match std::fs::read_to_string(&path) {
+                Ok(content) => content,
+                Err(err) => {
+                    p!("Skipping Include {path:?}: {err}");
+                    continue;
+                }
+            };
+            // checked upfront so a broken file isn't half applied
+            if let Err(err) = SshConfigParser::parse(Rule::file, &content) {
+                p!("Skipping Include {path:?}: {err}");
+                continue;
             }
-            Rule::option => {
-                let rules = &mut line.into_inner();
-                let keyword = rules.next().unwrap().as_str();
-                let argument = rules.next().unwrap().as_str();
-                hosts.entry(current_host).or_default().insert(keyword.to_lowercase(), argument);
+            chain.push(canonical);
+            if let Err(err) = self.read(&content, Some(&path), scope) {
+                p!("Include {path:?} partially read: {err:#}");
             }
-            _ => (),
+            self.chain.get_or_insert_with(Vec::new).pop();
         }
     }
-    let res: HashMap<_, _> = hosts
-        .into_iter()
-        .filter_map(|(name, o)| {
-            let name = name.to_string();
-            let profile = o.get("profile").copied().unwrap_or("others").to_string();
-            let platform = o.get("platform").copied().unwrap_or("others").to_string();
-            let platform = if platform == "win" { Platform::Win } else { Platform::Lnx };
-            let address = o.get("hostname")?.to_string();
-            let user = o.get("user").copied().map(String::from);
-            let key = o.get("identityfile").copied().map(String::from);
-            let bastion = o.get("proxyjump").copied().map(String::from);
-            Some((
-                name.clone(),
-                Host { name, profile, address, user, key, bastion, platform },
-            ))
-        })
-        .collect();
-    Ok(res)
+
+    fn into_hosts(self) -> HashMap<String, Host> {
+        let Self { hosts, mut sources, .. } = self;
+        hosts
+            .into_iter()
+            .filter_map(|(name, o)| {
+                let profile = o.get("profile").cloned().unwrap_or_else(|| "others".into());
+                let platform = o.get("platform").cloned().unwrap_or_else(|| "others".into());
+                let platform = if platform == "win" { Platform::Win } else { Platform::Lnx };
+                let address = o.get("hostname")?.to_string();
+                let user = o.get("user").cloned();
+                let key = o.get("identityfile").cloned();
+                let bastion = o.get("proxyjump").cloned();
+                let region = o.get("region").cloned();
+                let source = sources.remove(&name);
+                Some((
+                    name.clone(),
+                    Host { name, profile, address, user, key, bastion, platform, region, source },
+                ))
+            })
+            .collect()
+    }
 }
 
+/// Files matched by an `Include` pattern, sorted like ssh does; relative patterns
+/// are resolved against ~/.ssh
+fn include_paths(pattern: &str) -> Result<Vec<PathBuf>> {
+    let home = Config::home_dir();
+    let pattern = match pattern.strip_prefix("~/") {
+        Some(rest) => home.join(rest),
+        None => ssh_config_path().with_file_name(pattern),
+    };
+    let pattern = pattern.to_string_lossy();
+    let mut paths = glob::glob(&pattern)
+        .context(f!("invalid Include pattern {pattern}"))?
+        .filter_map(|p| p.ok())
+        .filter(|p| p.is_file())
+        .collect::<Vec<_>>();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Hosts of `path` and the files it includes, each `Include` read where it appears
+fn parse_file(path: &Path) -> Result<HashMap<String, Host>> {
+    let content = std::fs::read_to_string(path).context(f!("can't read {path:?}"))?;
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let mut collector = Collector { chain: Some(vec![canonical]), ..Collector::default() };
+    collector.read(&content, Some(path), None).context(f!("can't parse {path:?}"))?;
+    Ok(collector.into_hosts())
+}
+
+/// Hosts of ~/.ssh/config and the files it includes
 pub fn parse_ssh_config_from_host() -> Result<HashMap<String, Host>> {
-    let path = Config::home_dir().join(".ssh").join("config");
-    let ssh_config = std::fs::read_to_string(&path).context(f!("can't read {path:?}"))?;
-    parse_ssh_config(&ssh_config)
+    parse_file(&ssh_config_path())
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::prelude::*;
+    use itertools::Itertools;
+
     const SSH_CONFIG: &str = r#"
 Host *
 StrictHostKeyChecking no
@@ -126,4 +251,85 @@ Host RRM-01-(mago)
             Err(err) => panic!("{err:#}"),
         }
     }
+
+    #[test]
+    fn parse_ssh_config_reads_region() {
+        const WITH_REGION: &str = r#"
+Host web
+# profile prod,lnx,ap-southeast-2
+    HostName 10.0.0.1
+"#;
+        let res = super::parse_ssh_config(WITH_REGION).unwrap();
+        assert_eq!(res["web"].region.as_deref(), Some("ap-southeast-2"));
+    }
+
+    #[test]
+    fn parse_file_reads_includes_in_place() {
+        let dir = std::env::temp_dir().join(f!("ash-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("config.d")).unwrap();
+        let write = |name: &str, content: &str| std::fs::write(dir.join(name), content).unwrap();
+        let conf = |name: &str| dir.join("config.d").join(name).display().to_string();
+        write(
+            "config",
+            &f!(
+                "Include \"{}\"\nHost db\n    HostName 1.1.1.1\nHost web\n    HostName 2.2.2.2\n\
+                Host jump\n    Include {}\nMatch all\nInclude {}\n",
+                conf("*.conf"),
+                conf("scoped"),
+                conf("late")
+            ),
+        );
+        write("config.d/a.conf", "Host web\n    HostName 9.9.9.9\n");
+        write(
+            "config.d/b.conf",
+            &f!(
+                "Host cache\n    HostName 3.3.3.3\nInclude {}\n",
+                conf("late")
+            ),
+        );
+        write(
+            "config.d/c.conf",
+            "Host broken\n    HostName \"unterminated\n",
+        );
+        write(
+            "config.d/d.conf",
+            &f!("Include {}\n", dir.join("config").display()),
+        );
+        write(
+            "config.d/scoped",
+            "HostName 4.4.4.4\nHost other\n    HostName 5.5.5.5\n",
+        );
+        write(
+            "config.d/late",
+            "Host db\n    HostName 8.8.8.8\nHost queue\n    HostName 6.6.6.6\n",
+        );
+        let hosts = super::parse_file(&dir.join("config")).unwrap();
+        assert_eq!(
+            hosts.keys().sorted().collect_vec(),
+            ["cache", "db", "jump", "queue", "web"]
+        );
+        assert_eq!(hosts["web"].address, "9.9.9.9");
+        assert_eq!(hosts["db"].address, "1.1.1.1");
+        assert_eq!(hosts["jump"].address, "4.4.4.4");
+        assert_eq!(
+            hosts["web"].source,
+            Some(dir.join("config.d").join("a.conf"))
+        );
+        assert_eq!(hosts["jump"].source, Some(dir.join("config")));
+        assert_eq!(
+            hosts["queue"].source,
+            Some(dir.join("config.d").join("late"))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn first_value_wins() {
+        let res = super::parse_ssh_config(
+            "Host web\n    HostName 1.1.1.1\nHost web\n    HostName 2.2.2.2\n    User ubuntu\n",
+        )
+        .unwrap();
+        assert_eq!(res["web"].address, "1.1.1.1");
+        assert_eq!(res["web"].user.as_deref(), Some("ubuntu"));
+    }
 }
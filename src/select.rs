@@ -5,7 +5,7 @@ use crate::prelude::*;
 use crate::{config::Config, parsers};
 use dialoguer::console::{Color, Style};
 use dialoguer::theme::ColorfulTheme;
-use dialoguer::FuzzySelect;
+use dialoguer::{FuzzySelect, Input, Password};
 use fuzzy_matcher::skim::SkimMatcherV2;
 use fuzzy_matcher::FuzzyMatcher;
 use itertools::Itertools;
@@ -133,3 +133,24 @@ pub fn select_profile_then_host(hosts: &Hosts) -> Result<String> {
     History::update(&hosts.hosts[&selected]);
     Ok(selected)
 }
+
+pub fn input_mfa_code(mfa_serial: &str) -> Result<String> {
+    let code = Input::<String>::with_theme(&ColorfulTheme::default())
+        .with_prompt(f!("MFA code for {mfa_serial}"))
+        .validate_with(|code: &String| {
+            if code.len() == 6 && code.chars().all(|c| c.is_ascii_digit()) {
+                Ok(())
+            } else {
+                Err("MFA code must be 6 digits")
+            }
+        })
+        .interact_text()?;
+    Ok(code)
+}
+
+pub fn input_cache_passphrase() -> Result<String> {
+    let passphrase = Password::with_theme(&ColorfulTheme::default())
+        .with_prompt("Credential cache passphrase")
+        .interact()?;
+    Ok(passphrase)
+}
@@ -0,0 +1,250 @@
+//! The ash managed block of ~/.ssh/config
+use crate::config::Config;
+use crate::parsers::ssh_config_parser::{parse_ssh_config, Host};
+use crate::prelude::*;
+use itertools::Itertools;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+pub const BEGIN_MARKER: &str = "# BEGIN ash";
+pub const END_MARKER: &str = "# END ash";
+
+pub fn ssh_config_path() -> PathBuf {
+    Config::home_dir().join(".ssh").join("config")
+}
+
+/// Byte range of the first line equal to `marker` at or after `from`, newline included
+fn find_line(content: &str, marker: &str, from: usize) -> Option<(usize, usize)> {
+    let mut start = from;
+    for line in content[from..].split_inclusive('\n') {
+        if line.trim_end_matches(['\r', '\n']) == marker {
+            return Some((start, start + line.len()));
+        }
+        start += line.len();
+    }
+    None
+}
+
+/// Puts `hosts` between the ash markers of `existing`, leaving every other byte untouched.
+/// Without markers the block goes on top, so ash hosts win over entries below;
+/// the block ends with `Match all` so options that follow it stay global
+pub fn replace_managed_block(existing: &str, hosts: &str) -> Result<String> {
+    let mut block = f!("{BEGIN_MARKER}\n{}", hosts.trim_end_matches('\n'));
+    block += &f!("\nMatch all\n{END_MARKER}\n");
+    let Some((begin, after_end)) = managed_block(existing)? else {
+        return Ok(block + existing);
+    };
+    Ok(f!(
+        "{}{block}{}",
+        &existing[..begin],
+        &existing[after_end..]
+    ))
+}
+
+/// Byte range of the managed block, markers included
+fn managed_block(existing: &str) -> Result<Option<(usize, usize)>> {
+    let Some((begin, after_begin)) = find_line(existing, BEGIN_MARKER, 0) else {
+        return Ok(None);
+    };
+    let (_, after_end) = find_line(existing, END_MARKER, after_begin)
+        .ok_or_else(|| eyre!("'{BEGIN_MARKER}' without '{END_MARKER}', fix the ssh config"))?;
+    Ok(Some((begin, after_end)))
+}
+
+/// Annotated hosts outside the managed block that `hosts` take over, e.g. left from the days
+/// ash wrote the whole file; ssh uses the first match, so they no longer apply
+fn shadowed_hosts(existing: &str, hosts: &str) -> Vec<String> {
+    let outside = match managed_block(existing) {
+        Ok(Some((begin, after_end))) => f!("{}{}", &existing[..begin], &existing[after_end..]),
+        _ => existing.to_string(),
+    };
+    let (Ok(old), Ok(new)) = (parse_ssh_config(&outside), parse_ssh_config(hosts)) else {
+        return vec![];
+    };
+    old.into_keys().filter(|name| new.contains_key(name)).sorted().collect()
+}
+
+/// Tells which hand-kept hosts the managed block now hides
+fn report_shadowed(path: &Path, existing: &str, hosts: &str) {
+    let shadowed = shadowed_hosts(existing, hosts);
+    if !shadowed.is_empty() {
+        p!(
+            "Shadowed by ash hosts, remove them from {path:?}: {}",
+            shadowed.join(", ")
+        );
+    }
+}
+
+fn read_ssh_config(path: &Path) -> Result<String> {
+    match std::fs::read_to_string(path) {
+        Ok(existing) => Ok(existing),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(err) => Err(err).context(f!("can't read {path:?}")),
+    }
+}
+
+/// Rewrites the managed block of ~/.ssh/config with `hosts`, keeping the last `backups` copies
+/// of the previous file
+pub fn write_managed_block(hosts: &str, backups: usize) -> Result<()> {
+    let path = ssh_config_path();
+    let existing = read_ssh_config(&path)?;
+    let content = replace_managed_block(&existing, hosts).context(f!("{path:?}"))?;
+    if content == existing {
+        return Ok(());
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    backup(&path, backups)?;
+    report_shadowed(&path, &existing, hosts);
+    std::fs::write(&path, content).context(f!("can't write {path:?}"))
+}
+
+/// Prints the hosts that `write_managed_block` would add, remove or change
+pub fn preview_managed_block(hosts: &str) -> Result<()> {
+    let path = ssh_config_path();
+    let existing = read_ssh_config(&path)?;
+    let content = replace_managed_block(&existing, hosts).context(f!("{path:?}"))?;
+    let old = parse_ssh_config(&existing).context(f!("can't parse {path:?}"))?;
+    let new = parse_ssh_config(&content).context("can't parse the rendered template")?;
+    let diff = host_diff(&old, &new);
+    if diff.is_empty() {
+        p!("No host changes");
+    }
+    diff.iter().for_each(|line| p!("{line}"));
+    report_shadowed(&path, &existing, hosts);
+    Ok(())
+}
+
+/// `+ added`, `- removed` and `~ changed` lines, by host name
+fn host_diff(old: &HashMap<String, Host>, new: &HashMap<String, Host>) -> Vec<String> {
+    let names = old.keys().chain(new.keys()).unique().sorted();
+    names
+        .filter_map(|name| match (old.get(name), new.get(name)) {
+            (None, Some(h)) => Some(f!("+ {name} ({}) {}", h.profile, h.address)),
+            (Some(h), None) => Some(f!("- {name} ({}) {}", h.profile, h.address)),
+            (Some(o), Some(n)) => {
+                let mut changes = vec![];
+                if o.address != n.address {
+                    changes.push(f!("address {} -> {}", o.address, n.address));
+                }
+                if o.user != n.user {
+                    let user = |u: &Option<String>| u.clone().unwrap_or_else(|| "-".into());
+                    changes.push(f!("user {} -> {}", user(&o.user), user(&n.user)));
+                }
+                (!changes.is_empty())
+                    .then(|| f!("~ {name} ({}): {}", n.profile, changes.join(", ")))
+            }
+            (None, None) => None,
+        })
+        .collect()
+}
+
+/// Backups of the ssh config, newest first
+pub fn backups() -> Result<Vec<PathBuf>> {
+    let dir = Config::backups_dir();
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let backups = std::fs::read_dir(&dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| matches!(p.file_name(), Some(n) if n.to_string_lossy().starts_with("config.")))
+        .sorted()
+        .rev()
+        .collect();
+    Ok(backups)
+}
+
+/// Copies `path` to a timestamped backup, dropping all but the newest `keep`
+fn backup(path: &Path, keep: usize) -> Result<()> {
+    if !path.exists() || keep == 0 {
+        return Ok(());
+    }
+    let dir = Config::backups_dir();
+    std::fs::create_dir_all(&dir)?;
+    let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S-%3f");
+    let target = dir.join(f!("config.{stamp}"));
+    std::fs::copy(path, &target).context(f!("can't back up {path:?} to {target:?}"))?;
+    for old in backups()?.into_iter().skip(keep) {
+        std::fs::remove_file(&old).context(f!("can't remove old backup {old:?}"))?;
+    }
+    Ok(())
+}
+
+/// Puts back the `n`th newest backup (1 is the latest), backing up the current config first
+pub fn restore(n: usize, keep: usize) -> Result<()> {
+    let backups = backups()?;
+    ensure!(
+        !backups.is_empty(),
+        "No ssh config backups in {:?}",
+        Config::backups_dir()
+    );
+    let source = n
+        .checked_sub(1)
+        .and_then(|i| backups.get(i))
+        .ok_or_else(|| eyre!("Backup {n} not found, there are {} backups", backups.len()))?;
+    let content = std::fs::read(source).context(f!("can't read {source:?}"))?;
+    let path = ssh_config_path();
+    backup(&path, keep)?;
+    std::fs::write(&path, content).context(f!("can't write {path:?}"))?;
+    p!("Restored {path:?} from {source:?}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER_CONFIG: &str =
+        "Host *\r\n  ServerAliveInterval 60\r\n\r\nHost mine\n  HostName 1.2.3.4";
+
+    #[test]
+    fn replace_managed_block_keeps_the_rest() {
+        let first = replace_managed_block(USER_CONFIG, "Host web\n").unwrap();
+        assert_eq!(
+            first,
+            f!("# BEGIN ash\nHost web\nMatch all\n# END ash\n{USER_CONFIG}")
+        );
+        let moved = f!("{USER_CONFIG}\n{first}");
+        let second = replace_managed_block(&moved, "Host db\n").unwrap();
+        assert_eq!(second, moved.replace("Host web", "Host db"));
+        assert!(replace_managed_block("# BEGIN ash\nHost web\n", "").is_err());
+    }
+
+    #[test]
+    fn replace_managed_block_keeps_hand_written_hosts_below() {
+        let written = "Host *\n    ServerAliveInterval 60\n\nHost web\n# profile prod,lnx\n    HostName 1.1.1.1\n\nHost mine\n# profile home,lnx\n    HostName 9.9.9.9\n";
+        let hosts = "Host web\n# profile prod,lnx\n    HostName 2.2.2.2\n";
+        let updated = replace_managed_block(written, hosts).unwrap();
+        assert_eq!(
+            updated,
+            f!("# BEGIN ash\n{hosts}Match all\n# END ash\n{written}")
+        );
+        let parsed = parse_ssh_config(&updated).unwrap();
+        assert_eq!(parsed["web"].address, "2.2.2.2");
+        assert_eq!(parsed["mine"].address, "9.9.9.9");
+        assert_eq!(shadowed_hosts(written, hosts), ["web"]);
+        assert_eq!(shadowed_hosts(&updated, hosts), ["web"]);
+        assert!(shadowed_hosts(USER_CONFIG, hosts).is_empty());
+    }
+
+    #[test]
+    fn host_diff_reports_added_removed_and_changed() {
+        let old = parse_ssh_config(
+            "Host web\n# profile prod,lnx\n    HostName 1.1.1.1\n    User ubuntu\nHost old\n# profile prod,lnx\n    HostName 3.3.3.3\n",
+        )
+        .unwrap();
+        let new = parse_ssh_config(
+            "Host web\n# profile prod,lnx\n    HostName 2.2.2.2\n    User admin\nHost db\n# profile prod,lnx\n    HostName 4.4.4.4\n",
+        )
+        .unwrap();
+        assert_eq!(
+            host_diff(&old, &new),
+            [
+                "+ db (prod) 4.4.4.4",
+                "- old (prod) 3.3.3.3",
+                "~ web (prod): address 1.1.1.1 -> 2.2.2.2, user ubuntu -> admin"
+            ]
+        );
+    }
+}
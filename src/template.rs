@@ -0,0 +1,257 @@
+//! Handlebars setup for the ssh config template: helpers, partials from the config dir and
+//! strict mode, so a typo fails the update instead of writing an empty value
+use crate::aws::{ssh_hosts, Instance};
+use crate::config::{Config, TEMPLATE_FILE_NAME};
+use crate::inventory::Inventory;
+use crate::parsers::ssh_config_parser::parse_ssh_config;
+use crate::prelude::*;
+use handlebars::{
+    handlebars_helper, no_escape, Context, Handlebars, Helper, HelperDef, HelperResult, JsonRender,
+    Output, RenderContext, RenderError,
+};
+use itertools::Itertools;
+use serde::Serialize;
+use serde_json::json;
+use std::path::Path;
+
+handlebars_helper!(lower: |s: str| s.to_lowercase());
+handlebars_helper!(upper: |s: str| s.to_uppercase());
+handlebars_helper!(replace: |s: str, from: str, to: str| s.replace(from, to));
+handlebars_helper!(starts_with: |s: str, prefix: str| s.starts_with(prefix));
+handlebars_helper!(contains: |s: str, part: str| s.contains(part));
+handlebars_helper!(concat: |*args| args.iter().map(|a| a.render()).collect::<String>());
+// `*args` reads missing values as null even in strict mode, so it works on absent tags
+handlebars_helper!(default: |*args| {
+    let empty = |v: &&serde_json::Value| v.is_null() || v.as_str() == Some("");
+    let value = args.iter().find(|v| !empty(v)).copied();
+    value.cloned().unwrap_or_default()
+});
+
+/// `{{partial name value}}` renders the partial `name` with `value` as context, or nothing
+/// when there is no such partial, e.g. `{{partial (concat "profile-" t.profile) t}}`
+struct PartialHelper;
+
+impl HelperDef for PartialHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let name = h.param(0).map(|p| p.value().render()).unwrap_or_default();
+        if !r.has_template(&name) {
+            return Ok(());
+        }
+        let data = h.param(1).map(|p| p.value().clone()).unwrap_or_default();
+        let rendered = r
+            .render(&name, &data)
+            .map_err(|err| RenderError::new(f!("partial \"{name}\": {err}")))?;
+        out.write(&rendered)?;
+        Ok(())
+    }
+}
+
+fn register_file(hbs: &mut Handlebars, name: &str, path: &Path) -> Result<()> {
+    let content = std::fs::read_to_string(path).context(f!("can't read {path:?}"))?;
+    hbs.register_template_string(name, content).context(f!("in {path:?}"))?;
+    Ok(())
+}
+
+fn with_helpers() -> Handlebars<'static> {
+    let mut hbs = Handlebars::new();
+    hbs.set_strict_mode(true);
+    hbs.register_escape_fn(no_escape);
+    hbs.register_helper("lower", Box::new(lower));
+    hbs.register_helper("upper", Box::new(upper));
+    hbs.register_helper("replace", Box::new(replace));
+    hbs.register_helper("starts_with", Box::new(starts_with));
+    hbs.register_helper("contains", Box::new(contains));
+    hbs.register_helper("concat", Box::new(concat));
+    hbs.register_helper("default", Box::new(default));
+    hbs.register_helper("partial", Box::new(PartialHelper));
+    hbs
+}
+
+/// Registry with the helpers and, as partials, every other `*.hbs` file in the template dir
+/// named after its file stem (`win.hbs`, `lnx.hbs`, `profile-prod.hbs`, ...)
+pub fn registry(template: &Path) -> Result<Handlebars<'static>> {
+    let mut hbs = with_helpers();
+    let dir = template.parent().map(Path::to_path_buf).unwrap_or_else(Config::config_dir);
+    if let Ok(entries) = std::fs::read_dir(&dir) {
+        for path in entries.filter_map(|e| e.ok().map(|e| e.path())) {
+            let is_partial = matches!(path.extension(), Some(e) if e == "hbs") && path != template;
+            if let (true, Some(stem)) = (is_partial, path.file_stem()) {
+                register_file(&mut hbs, &stem.to_string_lossy(), &path)?;
+            }
+        }
+    }
+    register_file(&mut hbs, TEMPLATE_FILE_NAME, template)?;
+    Ok(hbs)
+}
+
+/// Renders `template` with `data`; the legacy `.prefix`/`.suffix` files are rendered
+/// around it, each under its own name so errors point at the right file and line
+pub fn render(template: &Path, data: &impl Serialize) -> Result<String> {
+    let mut hbs = registry(template)?;
+    let mut res = String::new();
+    for (name, ext) in [
+        ("prefix", Some("prefix")),
+        (TEMPLATE_FILE_NAME, None),
+        ("suffix", Some("suffix")),
+    ] {
+        if let Some(ext) = ext {
+            let path = template.with_file_name(f!("{TEMPLATE_FILE_NAME}.{ext}"));
+            if !path.exists() {
+                continue;
+            }
+            register_file(&mut hbs, name, &path)?;
+        }
+        res += &hbs.render(name, data)?;
+    }
+    Ok(res)
+}
+
+/// What went wrong when the rendered config was parsed back
+#[derive(Debug, Default, PartialEq)]
+pub struct CheckReport {
+    /// `Host` blocks the parser rejects, with the error
+    pub unparsable: Vec<(String, String)>,
+    /// Hosts that render but don't come back, e.g. without `HostName`
+    pub dropped: Vec<String>,
+    /// Hosts without a `# profile` line, listed under "others" by the selector
+    pub unannotated: Vec<String>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.unparsable.is_empty() && self.dropped.is_empty() && self.unannotated.is_empty()
+    }
+}
+
+/// Splits a rendered config into `(host, block)`, the text before the first `Host` included
+fn host_blocks(content: &str) -> Vec<(String, String)> {
+    let mut blocks = vec![("(before the first Host)".to_string(), String::new())];
+    for line in content.split_inclusive('\n') {
+        let mut words = line.split_whitespace();
+        if matches!(words.next(), Some(w) if w.eq_ignore_ascii_case("host")) {
+            blocks.push((words.join(" "), String::new()));
+        }
+        blocks.last_mut().unwrap().1 += line;
+    }
+    blocks
+}
+
+/// Parses `rendered` back and checks that every host in `expected` made it through
+pub fn check_rendered(rendered: &str, expected: &[String]) -> CheckReport {
+    let mut report = CheckReport::default();
+    let mut parsed = Default::default();
+    match parse_ssh_config(rendered) {
+        Ok(hosts) => parsed = hosts,
+        // the parser stops at the first error, so retry host by host to find all the bad ones
+        Err(_) => {
+            for (name, block) in host_blocks(rendered) {
+                match parse_ssh_config(&block) {
+                    Ok(hosts) => parsed.extend(hosts),
+                    Err(err) => report.unparsable.push((name, f!("{err:#}"))),
+                }
+            }
+        }
+    }
+    let failed = |name: &String| report.unparsable.iter().any(|(n, _)| n == name);
+    report.dropped =
+        expected.iter().filter(|n| !parsed.contains_key(*n) && !failed(n)).cloned().collect();
+    report.unannotated = parsed
+        .values()
+        .filter(|h| h.profile == "others")
+        .map(|h| h.name.clone())
+        .sorted()
+        .collect();
+    report
+}
+
+/// Hosts to render when there is no inventory yet: a linux one behind a bastion and a windows one
+fn sample_hosts() -> Result<Vec<Instance>> {
+    let host = |name: &str, platform: &str, user: &str, proxy_jump: &str| {
+        json!({
+            "name": name, "address": "10.0.0.10", "key": "~/.ssh/sample.pem", "profile": "sample",
+            "platform": platform, "proxy_jump": proxy_jump, "user": user, "port": "",
+            "region": "us-east-1", "instance_id": "i-0123456789abcdef0", "instance_type": "t3.micro",
+            "image_id": "ami-0123456789abcdef0", "availability_zone": "us-east-1a",
+            "vpc_id": "vpc-0123456789abcdef0", "subnet_id": "subnet-0123456789abcdef0",
+            "private_ip": "10.0.0.10", "public_ip": "", "ipv6": "",
+            "launch_time": "2024-01-01T00:00:00.000Z", "tags": {"Name": name, "env": "sample"}
+        })
+    };
+    let hosts = json!([
+        host("sample-bastion", "lnx", "ubuntu", ""),
+        host("sample-web", "lnx", "ubuntu", "sample-bastion"),
+        host("sample-win", "win", "Administrator", "sample-bastion"),
+    ]);
+    Ok(serde_json::from_value(hosts)?)
+}
+
+/// `ash template check`: renders the template against the inventory, or sample hosts when there
+/// is none, and parses the result back without touching ~/.ssh/config
+pub fn check(template: &Path, bastion_name: &str) -> Result<()> {
+    let mut hosts = ssh_hosts(&Inventory::load(), bastion_name);
+    if hosts.is_empty() {
+        p!("No inventory, checking against sample hosts");
+        hosts = sample_hosts()?;
+    }
+    let rendered = render(template, &hosts).context(f!("can't render {template:?}"))?;
+    let expected = hosts.iter().map(|h| h.name.clone()).collect_vec();
+    let report = check_rendered(&rendered, &expected);
+    for (name, err) in &report.unparsable {
+        p!("Can't parse host {name}: {err}");
+    }
+    for name in &report.dropped {
+        p!("Dropped host {name}: no HostName, or rendered under another name");
+    }
+    for name in &report.unannotated {
+        p!("Host {name} has no '# profile <profile>,<platform>' line");
+    }
+    ensure!(report.is_ok(), "{template:?} has problems");
+    p!("{template:?} ok, {} hosts", expected.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn helpers_render_in_strict_mode() {
+        let mut hbs = with_helpers();
+        hbs.register_partial("lnx", "User {{user}}").unwrap();
+        let data = json!({"name": "Web Server", "user": "ubuntu", "tags": {"env": "prod"}});
+        let tmpl = r#"{{lower (replace name " " "-")}} {{default tags.team tags.env}} {{partial "lnx" this}}{{partial "win" this}}"#;
+        assert_eq!(
+            hbs.render_template(tmpl, &data).unwrap(),
+            "web-server prod User ubuntu"
+        );
+        let err = hbs.render_template("line\n{{nme}}", &data).unwrap_err();
+        assert_eq!(err.line_no, Some(2));
+    }
+
+    #[test]
+    fn check_rendered_reports_bad_dropped_and_unannotated_hosts() {
+        let rendered = "Host a\n# profile prod,lnx\n    HostName 1.1.1.1\n\
+            Host b\n# profile prod\n    HostName 2.2.2.2\n\
+            Host c\n# profile prod,lnx\n    User ubuntu\n\
+            Host d\n    HostName 4.4.4.4\n";
+        let expected = ["a", "b", "c", "d"].map(String::from);
+        let report = check_rendered(rendered, &expected);
+        assert_eq!(report.unparsable.len(), 1);
+        assert_eq!(report.unparsable[0].0, "b");
+        assert_eq!(report.dropped, ["c"]);
+        assert_eq!(report.unannotated, ["d"]);
+        let sample = sample_hosts().unwrap();
+        let mut hbs = with_helpers();
+        hbs.register_template_string("t", crate::config::DEFAULT_TEMPLATE).unwrap();
+        let rendered = hbs.render("t", &sample).unwrap();
+        let names = sample.iter().map(|h| h.name.clone()).collect_vec();
+        assert!(check_rendered(&rendered, &names).is_ok());
+    }
+}